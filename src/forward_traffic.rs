@@ -0,0 +1,262 @@
+//! Implements the actual forwarding of traffic between the two halves of a tunnel,
+//! taking care of the framing that lets UDP datagrams be sent over a TCP stream.
+
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+/// Reads length-prefixed datagrams off a [`TcpStream`], retaining any partial progress
+/// made on a datagram across calls. This makes `read_datagram` safe to use as a
+/// `tokio::select!` branch: if the branch is not chosen (e.g. because the UDP side
+/// produced a datagram first), the in-progress read is simply resumed next time, rather
+/// than losing the bytes already pulled off the stream. This matters for
+/// `UdpForwardStrategy::Failover`, which reuses the same `tcp_stream` across multiple
+/// `process_udp_over_tcp` calls.
+pub struct DatagramReader {
+    length_buffer: [u8; 2],
+    body_buffer: [u8; MAX_DATAGRAM_SIZE],
+    state: ReadState,
+}
+
+enum ReadState {
+    ReadingLength { filled: usize },
+    ReadingBody { len: usize, filled: usize },
+}
+
+impl DatagramReader {
+    pub fn new() -> Self {
+        DatagramReader {
+            length_buffer: [0u8; 2],
+            body_buffer: [0u8; MAX_DATAGRAM_SIZE],
+            state: ReadState::ReadingLength { filled: 0 },
+        }
+    }
+
+    /// Reads one full length-prefixed datagram from `tcp_stream`, resuming any partial
+    /// read left over from an earlier, cancelled call. Returns `Ok(None)` if the stream
+    /// was closed before a new datagram could be read. The returned slice borrows this
+    /// reader's internal buffer and is only valid until the next call.
+    pub async fn read_datagram(
+        &mut self,
+        tcp_stream: &mut TcpStream,
+    ) -> io::Result<Option<&[u8]>> {
+        loop {
+            match &mut self.state {
+                ReadState::ReadingLength { filled } if *filled < self.length_buffer.len() => {
+                    let n = tcp_stream.read(&mut self.length_buffer[*filled..]).await?;
+                    if n == 0 {
+                        return Ok(None);
+                    }
+                    *filled += n;
+                }
+                ReadState::ReadingLength { .. } => {
+                    let len = usize::from(u16::from_be_bytes(self.length_buffer));
+                    self.state = ReadState::ReadingBody { len, filled: 0 };
+                }
+                ReadState::ReadingBody { len, filled } if *filled < *len => {
+                    let n = tcp_stream.read(&mut self.body_buffer[*filled..*len]).await?;
+                    if n == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "TCP stream closed in the middle of a datagram",
+                        ));
+                    }
+                    *filled += n;
+                }
+                ReadState::ReadingBody { len, .. } => {
+                    let len = *len;
+                    self.state = ReadState::ReadingLength { filled: 0 };
+                    return Ok(Some(&self.body_buffer[..len]));
+                }
+            }
+        }
+    }
+}
+
+/// The maximum size of a UDP datagram this crate supports forwarding. UDP datagrams can
+/// never exceed this size, since that's the limit of the length prefix used to frame
+/// them on the TCP side.
+const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+/// How long to wait before retrying a connected UDP socket operation that failed with
+/// `ConnectionRefused` (delivered as an ICMP port-unreachable for the peer it's
+/// connected to).
+const PORT_UNREACHABLE_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// The outcome of [`process_udp_over_tcp`], letting the caller decide what to do next
+/// (e.g. failing over to a different UDP target).
+#[derive(Debug, PartialEq, Eq)]
+pub enum ForwardResult {
+    /// The TCP stream was closed, or an unrecoverable error happened.
+    Closed,
+    /// The UDP socket kept getting ICMP port-unreachable even after retrying, so
+    /// forwarding to it was given up on.
+    PortUnreachable,
+}
+
+/// Forwards traffic between `udp_socket` and `tcp_stream` until either side is closed
+/// or an unrecoverable error happens. UDP datagrams are sent on the TCP stream prefixed
+/// with their length, as a big-endian `u16`.
+///
+/// A momentary ICMP port-unreachable on the connected `udp_socket` (surfaced by the OS
+/// as `ConnectionRefused` on the next `send`/`recv`) is treated as transient and retried
+/// up to `udp_max_port_unreachable_retries` times before giving up.
+pub async fn process_udp_over_tcp(
+    udp_socket: UdpSocket,
+    tcp_stream: &mut TcpStream,
+    tcp_reader: &mut DatagramReader,
+    udp_max_port_unreachable_retries: u32,
+) -> ForwardResult {
+    let mut udp2tcp_buffer = [0u8; MAX_DATAGRAM_SIZE];
+    let mut port_unreachable_retries = 0;
+
+    loop {
+        tokio::select! {
+            udp_result = udp_socket.recv(&mut udp2tcp_buffer) => {
+                match udp_result {
+                    Ok(datagram_size) => {
+                        port_unreachable_retries = 0;
+                        if let Err(error) = forward_datagram_to_tcp(
+                            tcp_stream,
+                            &udp2tcp_buffer[..datagram_size],
+                        ).await {
+                            log::error!("Error forwarding UDP datagram to TCP: {}", error);
+                            return ForwardResult::Closed;
+                        }
+                    }
+                    Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => {
+                        if !retry_port_unreachable(
+                            &mut port_unreachable_retries,
+                            udp_max_port_unreachable_retries,
+                        ).await {
+                            return ForwardResult::PortUnreachable;
+                        }
+                    }
+                    Err(error) => {
+                        log::error!("Error reading from UDP socket: {}", error);
+                        return ForwardResult::Closed;
+                    }
+                }
+            }
+            tcp_result = tcp_reader.read_datagram(tcp_stream) => {
+                match tcp_result {
+                    Ok(Some(datagram)) => {
+                        match udp_socket.send(datagram).await {
+                            Ok(_) => port_unreachable_retries = 0,
+                            Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => {
+                                if !retry_port_unreachable(
+                                    &mut port_unreachable_retries,
+                                    udp_max_port_unreachable_retries,
+                                ).await {
+                                    return ForwardResult::PortUnreachable;
+                                }
+                            }
+                            Err(error) => {
+                                log::error!("Error writing to UDP socket: {}", error);
+                                return ForwardResult::Closed;
+                            }
+                        }
+                    }
+                    Ok(None) => {
+                        log::debug!("TCP stream closed");
+                        return ForwardResult::Closed;
+                    }
+                    Err(error) => {
+                        log::error!("Error reading from TCP stream: {}", error);
+                        return ForwardResult::Closed;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Bumps the port-unreachable retry counter and sleeps for a short, fixed delay.
+/// Returns `false` once `max_retries` has been exceeded, meaning the caller should give
+/// up on this UDP socket.
+async fn retry_port_unreachable(retries: &mut u32, max_retries: u32) -> bool {
+    *retries += 1;
+    if *retries > max_retries {
+        log::error!(
+            "UDP port unreachable after {} retries, closing forwarding",
+            max_retries
+        );
+        return false;
+    }
+    log::warn!(
+        "UDP port unreachable, retrying ({}/{})",
+        retries,
+        max_retries
+    );
+    tokio::time::sleep(PORT_UNREACHABLE_RETRY_DELAY).await;
+    true
+}
+
+/// Writes `datagram` on `tcp_stream`, prefixed with its length as a big-endian `u16`.
+async fn forward_datagram_to_tcp(
+    tcp_stream: &mut TcpStream,
+    datagram: &[u8],
+) -> io::Result<()> {
+    tcp_stream
+        .write_all(&(datagram.len() as u16).to_be_bytes())
+        .await?;
+    tcp_stream.write_all(datagram).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Returns a connected `(server, client)` `TcpStream` pair over loopback.
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (accept_result, connect_result) =
+            tokio::join!(listener.accept(), TcpStream::connect(addr));
+        (accept_result.unwrap().0, connect_result.unwrap())
+    }
+
+    #[tokio::test]
+    async fn read_datagram_resumes_a_partial_read_after_being_cancelled() {
+        let (mut server, mut client) = tcp_pair().await;
+        let datagram = b"hello, world!";
+        client
+            .write_all(&(datagram.len() as u16).to_be_bytes())
+            .await
+            .unwrap();
+        // Only the first 5 bytes of the body have arrived so far.
+        client.write_all(&datagram[..5]).await.unwrap();
+
+        let mut reader = DatagramReader::new();
+        // Not enough data is available yet, so this future never resolves within the
+        // timeout and gets dropped mid-read, in the same way a `tokio::select!` would
+        // drop it if the UDP side produced a datagram first.
+        let read = tokio::time::timeout(
+            Duration::from_millis(50),
+            reader.read_datagram(&mut server),
+        )
+        .await;
+        assert!(read.is_err(), "expected the read to still be pending");
+
+        // The rest of the body arrives later. A correctly resumable reader picks up
+        // from where it left off instead of re-reading the length prefix from what is
+        // now mid-body data.
+        client.write_all(&datagram[5..]).await.unwrap();
+        let received = reader
+            .read_datagram(&mut server)
+            .await
+            .unwrap()
+            .expect("stream not closed");
+        assert_eq!(received, datagram);
+    }
+
+    #[tokio::test]
+    async fn read_datagram_returns_none_on_a_clean_close() {
+        let (mut server, client) = tcp_pair().await;
+        drop(client);
+        let result = DatagramReader::new().read_datagram(&mut server).await;
+        assert!(result.unwrap().is_none());
+    }
+}
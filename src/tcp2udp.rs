@@ -5,19 +5,57 @@ use err_context::{BoxedErrorExt as _, ResultExt as _};
 use std::convert::Infallible;
 use std::fmt;
 use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU64;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
-use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+use tokio::net::{lookup_host, TcpListener, TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::watch;
 
 #[derive(Debug, StructOpt)]
 pub struct Options {
-    /// The IP and TCP port(s) to listen to for incoming traffic from udp2tcp.
-    /// Supports binding multiple TCP sockets.
+    /// The IP/hostname and TCP port(s) to listen to for incoming traffic from udp2tcp.
+    /// Supports binding multiple TCP sockets. A hostname that resolves to more than one
+    /// address will have all of them bound.
     #[structopt(long = "tcp-listen", required(true))]
-    pub tcp_listen_addrs: Vec<SocketAddr>,
+    pub tcp_listen_addrs: Vec<String>,
 
-    #[structopt(long = "udp-forward")]
-    /// The IP and UDP port to forward all traffic to.
-    pub udp_forward_addr: SocketAddr,
+    /// The IP/hostname and UDP port to forward traffic to. Can be given more than once
+    /// to forward to multiple targets - see `--udp-forward-strategy` for how a target
+    /// is then picked for each incoming TCP connection. If a hostname resolves to
+    /// multiple addresses, the first one is used.
+    #[structopt(long = "udp-forward", required(true))]
+    pub udp_forward_addrs: Vec<String>,
+
+    /// How to pick which `--udp-forward` target to use for an incoming TCP connection,
+    /// when more than one is given. `round-robin` cycles through the targets one
+    /// connection at a time. `failover` always tries the first target first, and only
+    /// moves on to the next one if it turns out to be unreachable.
+    #[structopt(long = "udp-forward-strategy", default_value = "round-robin")]
+    pub udp_forward_strategy: UdpForwardStrategy,
+
+    /// Re-resolve the hostnames given to `--udp-forward` every this many seconds and
+    /// switch to forwarding to the new addresses if they have changed. Useful if a
+    /// forward target is behind a dynamic DNS record. Off by default, meaning the
+    /// hostnames are resolved once, at startup. Must not be zero.
+    #[structopt(long = "udp-forward-reresolve-interval")]
+    pub udp_forward_reresolve_interval: Option<NonZeroU64>,
+
+    /// How many times in a row the connected UDP socket may return "connection refused"
+    /// (delivered by the OS as an ICMP port-unreachable), each followed by a short
+    /// pause, before forwarding to that UDP target is given up on. The datagram that
+    /// ran into the error is not resent.
+    #[structopt(long = "udp-max-port-unreachable-retries", default_value = "3")]
+    pub udp_max_port_unreachable_retries: u32,
+
+    /// Address of an echo server used to verify, at startup, that all `--tcp-listen`
+    /// ports are actually reachable from the outside (e.g. not blocked by a firewall
+    /// or missing a NAT port forward). If given, `run` asks the echo server to connect
+    /// back to every bound port before it starts serving traffic.
+    #[structopt(long = "verify-reachable")]
+    pub verify_reachable: Option<SocketAddr>,
 
     /// Which local IP to bind the UDP socket to.
     #[structopt(long = "udp-bind", default_value = "0.0.0.0")]
@@ -27,11 +65,43 @@ pub struct Options {
     pub tcp_options: crate::tcp_options::TcpOptions,
 }
 
+/// How a UDP forward target is picked, for an incoming TCP connection, among the
+/// targets given via `--udp-forward`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpForwardStrategy {
+    /// Cycle through the targets, one connection at a time.
+    RoundRobin,
+    /// Always prefer the first target, falling back to the next ones in order if a
+    /// target turns out to be unreachable.
+    Failover,
+}
+
+impl FromStr for UdpForwardStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(UdpForwardStrategy::RoundRobin),
+            "failover" => Ok(UdpForwardStrategy::Failover),
+            invalid => Err(format!(
+                "Invalid udp forward strategy \"{}\", must be round-robin or failover",
+                invalid
+            )),
+        }
+    }
+}
+
 /// Error returned from [`run`] if something goes wrong.
 #[derive(Debug)]
 pub enum Tcp2UdpError {
     /// No TCP listen addresses given in the `Options`.
     NoTcpListenAddrs,
+    /// A hostname did not resolve to any addresses.
+    NoAddressesForHostname(String),
+    /// A TCP listen port could not be verified as reachable via `--verify-reachable`.
+    UnreachablePort(SocketAddr),
+    /// The `--verify-reachable` echo server never responded to our probes.
+    EchoServerUnreachable(SocketAddr),
 }
 
 impl fmt::Display for Tcp2UdpError {
@@ -39,6 +109,15 @@ impl fmt::Display for Tcp2UdpError {
         use Tcp2UdpError::*;
         match self {
             NoTcpListenAddrs => "Invalid options, no TCP listen addresses".fmt(f),
+            NoAddressesForHostname(hostname) => {
+                write!(f, "Hostname resolved to zero addresses: {}", hostname)
+            }
+            UnreachablePort(addr) => {
+                write!(f, "TCP listen port could not be verified as reachable: {}", addr)
+            }
+            EchoServerUnreachable(addr) => {
+                write!(f, "Echo server {} did not respond to --verify-reachable probes", addr)
+            }
         }
     }
 }
@@ -48,10 +127,28 @@ impl std::error::Error for Tcp2UdpError {
         use Tcp2UdpError::*;
         match self {
             NoTcpListenAddrs => None,
+            NoAddressesForHostname(..) => None,
+            UnreachablePort(..) => None,
+            EchoServerUnreachable(..) => None,
         }
     }
 }
 
+/// Resolves `hostname` (a `host:port` string) to all of the `SocketAddr`s it points to.
+/// Returns [`Tcp2UdpError::NoAddressesForHostname`] if it resolves to zero addresses.
+async fn resolve(hostname: &str) -> Result<Vec<SocketAddr>, Box<dyn std::error::Error>> {
+    let addrs: Vec<SocketAddr> = lookup_host(hostname)
+        .await
+        .with_context(|_| format!("Failed to resolve {}", hostname))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(Box::new(Tcp2UdpError::NoAddressesForHostname(
+            hostname.to_owned(),
+        )));
+    }
+    Ok(addrs)
+}
+
 /// Sets up TCP listening sockets on all addresses in `Options::tcp_listen_addrs`.
 /// If binding a listening socket fails this returns an error. Otherwise the function
 /// will continue indefinitely to accept incoming connections and forward to UDP.
@@ -61,21 +158,189 @@ pub async fn run(options: Options) -> Result<Infallible, Box<dyn std::error::Err
         return Err(Box::new(Tcp2UdpError::NoTcpListenAddrs));
     }
 
-    let mut join_handles = Vec::with_capacity(options.tcp_listen_addrs.len());
-    for tcp_listen_addr in options.tcp_listen_addrs {
-        let tcp_listener = create_listening_socket(tcp_listen_addr, &options.tcp_options)?;
-        log::info!("Listening on {}/TCP", tcp_listener.local_addr().unwrap());
+    let mut udp_forward_addrs = Vec::with_capacity(options.udp_forward_addrs.len());
+    for udp_forward_hostname in &options.udp_forward_addrs {
+        udp_forward_addrs.push(*resolve(udp_forward_hostname).await?.first().unwrap());
+    }
+    let (udp_forward_addrs_tx, udp_forward_addrs_rx) = watch::channel(udp_forward_addrs);
+
+    if let Some(interval) = options.udp_forward_reresolve_interval {
+        let udp_forward_hostnames = options.udp_forward_addrs.clone();
+        tokio::spawn(re_resolve_udp_forward_addrs(
+            udp_forward_hostnames,
+            Duration::from_secs(interval.get()),
+            udp_forward_addrs_tx,
+        ));
+    }
+
+    let round_robin_counter = Arc::new(AtomicUsize::new(0));
+
+    let mut tcp_listeners = Vec::new();
+    for tcp_listen_host in &options.tcp_listen_addrs {
+        for tcp_listen_addr in resolve(tcp_listen_host).await? {
+            let tcp_listener = create_listening_socket(tcp_listen_addr, &options.tcp_options)?;
+            log::info!("Listening on {}/TCP", tcp_listener.local_addr().unwrap());
+            tcp_listeners.push(tcp_listener);
+        }
+    }
+
+    if let Some(echo_server_addr) = options.verify_reachable {
+        let bound_addrs: Vec<SocketAddr> = tcp_listeners
+            .iter()
+            .map(|tcp_listener| tcp_listener.local_addr().unwrap())
+            .collect();
+        verify_reachable(echo_server_addr, &bound_addrs).await?;
+    }
 
+    let mut join_handles = Vec::with_capacity(tcp_listeners.len());
+    for tcp_listener in tcp_listeners {
         let udp_bind_ip = options.udp_bind_ip;
-        let udp_forward_addr = options.udp_forward_addr;
+        let udp_forward_addrs_rx = udp_forward_addrs_rx.clone();
+        let udp_forward_strategy = options.udp_forward_strategy;
+        let udp_max_port_unreachable_retries = options.udp_max_port_unreachable_retries;
+        let round_robin_counter = round_robin_counter.clone();
         join_handles.push(tokio::spawn(async move {
-            process_tcp_listener(tcp_listener, udp_bind_ip, udp_forward_addr).await;
+            process_tcp_listener(
+                tcp_listener,
+                udp_bind_ip,
+                udp_forward_addrs_rx,
+                udp_forward_strategy,
+                round_robin_counter,
+                udp_max_port_unreachable_retries,
+            )
+            .await;
         }));
     }
     futures::future::join_all(join_handles).await;
     unreachable!("Listening TCP sockets never exit");
 }
 
+/// Number of times to ask the echo server again, after the first attempt, to verify a
+/// port before giving up on it.
+const VERIFY_REACHABLE_RETRIES: u32 = 2;
+/// How long to wait for the echo server to report back before retrying.
+const VERIFY_REACHABLE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Asks the echo server at `echo_server_addr` to connect back to every address in
+/// `tcp_listen_addrs`, to verify that they are reachable through any NAT/firewall in
+/// front of this host. Retries a couple of times before giving up on a port. Returns
+/// [`Tcp2UdpError::UnreachablePort`] for the first port that could not be verified.
+async fn verify_reachable(
+    echo_server_addr: SocketAddr,
+    tcp_listen_addrs: &[SocketAddr],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let probe_socket = UdpSocket::bind(SocketAddr::new(
+        if echo_server_addr.is_ipv6() {
+            IpAddr::from([0u16; 8])
+        } else {
+            IpAddr::from([0u8; 4])
+        },
+        0,
+    ))
+    .await
+    .context("Failed to bind UDP probe socket for --verify-reachable")?;
+
+    let addrs: Vec<String> = tcp_listen_addrs.iter().map(SocketAddr::to_string).collect();
+    let request = addrs.join(",");
+
+    let mut response_buffer = [0u8; 1024];
+    for attempt in 0..=VERIFY_REACHABLE_RETRIES {
+        probe_socket
+            .send_to(request.as_bytes(), echo_server_addr)
+            .await
+            .with_context(|_| format!("Failed to send probe to {}", echo_server_addr))?;
+
+        let recv_result = tokio::time::timeout(
+            VERIFY_REACHABLE_TIMEOUT,
+            probe_socket.recv(&mut response_buffer),
+        )
+        .await;
+        // Keyed by the full `SocketAddr`, not just the port, so that two listen
+        // addrs sharing a port (e.g. the IPv4 and IPv6 binds of the same port) can't
+        // be mistaken for each other.
+        let reachable_addrs: std::collections::HashSet<SocketAddr> = match recv_result {
+            Ok(Ok(len)) => std::str::from_utf8(&response_buffer[..len])
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|addr| addr.trim().parse().ok())
+                .collect(),
+            _ => {
+                log::warn!(
+                    "No response from echo server {} (attempt {}/{})",
+                    echo_server_addr,
+                    attempt + 1,
+                    VERIFY_REACHABLE_RETRIES + 1
+                );
+                if attempt == VERIFY_REACHABLE_RETRIES {
+                    return Err(Box::new(Tcp2UdpError::EchoServerUnreachable(
+                        echo_server_addr,
+                    )));
+                }
+                continue;
+            }
+        };
+
+        match tcp_listen_addrs
+            .iter()
+            .find(|addr| !reachable_addrs.contains(addr))
+        {
+            None => return Ok(()),
+            Some(unreachable_addr) if attempt == VERIFY_REACHABLE_RETRIES => {
+                return Err(Box::new(Tcp2UdpError::UnreachablePort(*unreachable_addr)));
+            }
+            Some(_) => continue,
+        }
+    }
+    unreachable!("loop always returns on its last iteration");
+}
+
+/// Runs forever, periodically re-resolving every hostname in `udp_forward_hostnames`
+/// (in order) and publishing the resulting addresses through `udp_forward_addrs_tx`.
+/// If a hostname fails to re-resolve, its last-known-good address is kept and a
+/// warning is logged.
+async fn re_resolve_udp_forward_addrs(
+    udp_forward_hostnames: Vec<String>,
+    interval: Duration,
+    udp_forward_addrs_tx: watch::Sender<Vec<SocketAddr>>,
+) {
+    let mut interval = tokio::time::interval(interval);
+    // `interval()` ticks immediately on its first call. The hostnames were already
+    // resolved once, right before this task was spawned, so skip that first tick.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let mut addrs = udp_forward_addrs_tx.borrow().clone();
+        let mut changed = false;
+        for (i, udp_forward_hostname) in udp_forward_hostnames.iter().enumerate() {
+            match resolve(udp_forward_hostname).await {
+                Ok(resolved) => {
+                    // Compared against the full resolved set, rather than just its
+                    // first address, so that a DNS server returning the same
+                    // addresses in a different order doesn't look like a change.
+                    if !resolved.contains(&addrs[i]) {
+                        let addr = resolved[0];
+                        log::info!(
+                            "UDP forward address for {} changed to {}",
+                            udp_forward_hostname,
+                            addr
+                        );
+                        addrs[i] = addr;
+                        changed = true;
+                    }
+                }
+                Err(error) => log::warn!(
+                    "Failed to re-resolve {}, keeping last-known-good address: {}",
+                    udp_forward_hostname,
+                    error,
+                ),
+            }
+        }
+        if changed && udp_forward_addrs_tx.send(addrs).is_err() {
+            return;
+        }
+    }
+}
+
 fn create_listening_socket(
     addr: SocketAddr,
     options: &crate::tcp_options::TcpOptions,
@@ -100,17 +365,34 @@ fn create_listening_socket(
 async fn process_tcp_listener(
     tcp_listener: TcpListener,
     udp_bind_ip: IpAddr,
-    udp_forward_addr: SocketAddr,
+    udp_forward_addrs_rx: watch::Receiver<Vec<SocketAddr>>,
+    udp_forward_strategy: UdpForwardStrategy,
+    round_robin_counter: Arc<AtomicUsize>,
+    udp_max_port_unreachable_retries: u32,
 ) -> ! {
     loop {
         match tcp_listener.accept().await {
             Ok((tcp_stream, tcp_peer_addr)) => {
                 log::debug!("Incoming connection from {}/TCP", tcp_peer_addr);
 
+                let udp_peer_addrs = udp_forward_addrs_rx.borrow().clone();
+                let udp_peer_addrs = match udp_forward_strategy {
+                    UdpForwardStrategy::RoundRobin => {
+                        let i = round_robin_counter.fetch_add(1, Ordering::Relaxed)
+                            % udp_peer_addrs.len();
+                        vec![udp_peer_addrs[i]]
+                    }
+                    UdpForwardStrategy::Failover => udp_peer_addrs,
+                };
                 tokio::spawn(async move {
-                    if let Err(error) =
-                        process_socket(tcp_stream, tcp_peer_addr, udp_bind_ip, udp_forward_addr)
-                            .await
+                    if let Err(error) = process_socket(
+                        tcp_stream,
+                        tcp_peer_addr,
+                        udp_bind_ip,
+                        udp_peer_addrs,
+                        udp_max_port_unreachable_retries,
+                    )
+                    .await
                     {
                         log::error!("Error: {}", error.display("\nCaused by: "));
                     }
@@ -121,42 +403,156 @@ async fn process_tcp_listener(
     }
 }
 
-/// Sets up a UDP socket bound to `udp_bind_ip` and connected to `udp_peer_addr` and forwards
-/// traffic between that UDP socket and the given `tcp_stream` until the `tcp_stream` is closed.
-/// `tcp_peer_addr` should be the remote addr that `tcp_stream` is connected to.
+/// Sets up a UDP socket bound to `udp_bind_ip` and connected to the first address in
+/// `udp_peer_addrs`, and forwards traffic between that UDP socket and the given
+/// `tcp_stream` until the `tcp_stream` is closed. If forwarding gives up on a target
+/// because it is unreachable, and `udp_peer_addrs` has more than one entry, the next
+/// address in the list is tried instead (this is how `UdpForwardStrategy::Failover`
+/// moves on to a backup target). `tcp_peer_addr` should be the remote addr that
+/// `tcp_stream` is connected to.
 async fn process_socket(
-    tcp_stream: TcpStream,
+    mut tcp_stream: TcpStream,
     tcp_peer_addr: SocketAddr,
     udp_bind_ip: IpAddr,
-    udp_peer_addr: SocketAddr,
+    udp_peer_addrs: Vec<SocketAddr>,
+    udp_max_port_unreachable_retries: u32,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let udp_bind_addr = SocketAddr::new(udp_bind_ip, 0);
+    // Owned here, rather than inside `process_udp_over_tcp`, so that a partially read
+    // TCP datagram survives failing over from one UDP target to the next.
+    let mut tcp_reader = crate::forward_traffic::DatagramReader::new();
 
-    let udp_socket = UdpSocket::bind(udp_bind_addr)
-        .await
-        .with_context(|_| format!("Failed to bind UDP socket to {}", udp_bind_addr))?;
-    udp_socket
-        .connect(udp_peer_addr)
-        .await
-        .with_context(|_| format!("Failed to connect UDP socket to {}", udp_peer_addr))?;
+    let mut udp_peer_addrs = udp_peer_addrs.into_iter();
+    loop {
+        let udp_peer_addr = udp_peer_addrs
+            .next()
+            .expect("at least one udp forward address");
 
-    log::debug!(
-        "UDP socket bound to {} and connected to {}",
+        let udp_socket = UdpSocket::bind(udp_bind_addr)
+            .await
+            .with_context(|_| format!("Failed to bind UDP socket to {}", udp_bind_addr))?;
         udp_socket
-            .local_addr()
-            .ok()
-            .as_ref()
-            .map(|item| -> &dyn fmt::Display { &*item })
-            .unwrap_or(&"unknown"),
-        udp_peer_addr
-    );
-
-    crate::forward_traffic::process_udp_over_tcp(udp_socket, tcp_stream).await;
-    log::debug!(
-        "Closing forwarding for {}/TCP <-> {}/UDP",
-        tcp_peer_addr,
-        udp_peer_addr
-    );
-
-    Ok(())
+            .connect(udp_peer_addr)
+            .await
+            .with_context(|_| format!("Failed to connect UDP socket to {}", udp_peer_addr))?;
+
+        log::debug!(
+            "UDP socket bound to {} and connected to {}",
+            udp_socket
+                .local_addr()
+                .ok()
+                .as_ref()
+                .map(|item| -> &dyn fmt::Display { &*item })
+                .unwrap_or(&"unknown"),
+            udp_peer_addr
+        );
+
+        let result = crate::forward_traffic::process_udp_over_tcp(
+            udp_socket,
+            &mut tcp_stream,
+            &mut tcp_reader,
+            udp_max_port_unreachable_retries,
+        )
+        .await;
+        log::debug!(
+            "Closing forwarding for {}/TCP <-> {}/UDP",
+            tcp_peer_addr,
+            udp_peer_addr
+        );
+
+        match result {
+            // The TCP->UDP datagram that triggered this `PortUnreachable` (if any) was
+            // already read off `tcp_stream` and handed to the now-abandoned UDP socket;
+            // it is not resent to the next target and is simply lost. UDP is lossy
+            // anyway, so this is tolerated rather than worked around.
+            crate::forward_traffic::ForwardResult::PortUnreachable
+                if !udp_peer_addrs.as_slice().is_empty() =>
+            {
+                log::warn!(
+                    "UDP forward target {} unreachable, failing over to next target",
+                    udp_peer_addr
+                );
+                continue;
+            }
+            _ => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Returns a connected `(server, client)` `TcpStream` pair over loopback.
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (accept_result, connect_result) =
+            tokio::join!(listener.accept(), TcpStream::connect(addr));
+        (accept_result.unwrap().0, connect_result.unwrap())
+    }
+
+    #[tokio::test]
+    async fn process_socket_fails_over_to_the_next_reachable_udp_target() {
+        // Nobody is listening on this address, so it triggers ICMP port-unreachable.
+        let unreachable_target = {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket.local_addr().unwrap()
+        };
+
+        // A real UDP echo target to fail over to.
+        let live_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let live_target = live_socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            if let Ok((n, peer)) = live_socket.recv_from(&mut buf).await {
+                let _ = live_socket.send_to(&buf[..n], peer).await;
+            }
+        });
+
+        let (server_tcp, mut client_tcp) = tcp_pair().await;
+        let datagram = b"ping";
+
+        let forward_task = tokio::spawn(process_socket(
+            server_tcp,
+            "127.0.0.1:1".parse().unwrap(),
+            IpAddr::from([127, 0, 0, 1]),
+            vec![unreachable_target, live_target],
+            1,
+        ));
+
+        // With one retry allowed, forwarding only gives up on `unreachable_target` after
+        // its second port-unreachable, and the datagrams that ran into them are lost
+        // rather than resent. So keep sending the datagram until one comes back.
+        // Reading back the echoed datagram over the TCP side proves forwarding failed
+        // over to `live_target` rather than giving up after `unreachable_target`.
+        let mut length_buffer = [0u8; 2];
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            assert!(attempts <= 20, "datagram was never echoed back");
+            client_tcp
+                .write_all(&(datagram.len() as u16).to_be_bytes())
+                .await
+                .unwrap();
+            client_tcp.write_all(datagram).await.unwrap();
+            let read = tokio::time::timeout(
+                Duration::from_millis(300),
+                client_tcp.read_exact(&mut length_buffer),
+            )
+            .await;
+            if let Ok(result) = read {
+                result.unwrap();
+                break;
+            }
+        }
+        let len = usize::from(u16::from_be_bytes(length_buffer));
+        let mut body = vec![0u8; len];
+        client_tcp.read_exact(&mut body).await.unwrap();
+        assert_eq!(body, datagram);
+
+        drop(client_tcp);
+        forward_task.await.unwrap().unwrap();
+    }
 }